@@ -1,114 +1,304 @@
 use std::ops::Not;
 use std::fmt;
+use std::io::Write;
 
 pub mod parser;
 pub mod solver;
 
+/// The index into `Formula::watches` that a literal occupies: each variable has two slots, one
+/// per polarity.
+fn lit_index(lit: Literal) -> usize {
+    lit.id * 2 + lit.negated as usize
+}
+
+enum WatchResult {
+    /// The watch was moved to a new, non-false literal in the clause
+    Moved,
+    /// The clause is already satisfied by its other watched literal
+    Satisfied,
+    /// No replacement watch was found and the other watched literal is unassigned
+    Unit(Literal),
+    /// No replacement watch was found and the other watched literal is false
+    Conflict,
+}
+
 /// A propositional formula in CNF
 pub struct Formula {
-    clauses: Vec<(Clause, bool)>,
+    clauses: Vec<Clause>,
     assignment: Assignment,
-    // reverse index from literals to indices of clauses that have those literals and whether the literal is negated
-    clause_indices: Vec<Vec<(usize, bool)>>,
+    // `watches[lit_index(l)]` holds the clauses with a watched literal equal to `l`; they are
+    // revisited whenever `l` is falsified
+    watches: Vec<Vec<usize>>,
     assign_history: Vec<Vec<Literal>>,
-    remaining_clauses: usize,
     unsolvable: bool,
-    next_literal_id: usize,
+    num_assigned: usize,
+    // the decision level each variable was assigned at, valid only while assigned
+    var_level: Vec<usize>,
+    // the clause that forced each variable's assignment via unit propagation, `None` for a decision
+    var_reason: Vec<Option<usize>>,
+    // the clause found empty by the most recent `assign_with_reason`, if any
+    conflict_clause: Option<usize>,
+    // VSIDS activity score per variable
+    activity: Vec<f64>,
+    // amount `activity` is bumped by; grows over time so recent conflicts weigh more
+    activity_inc: f64,
+    // unassigned variables ordered by `activity`
+    var_heap: VarHeap,
+    // the polarity each variable was last assigned, reused when it's next branched on
+    phase: Vec<bool>,
+    // the DRAT proof being written, if the caller asked for one via `solve_with_proof`
+    proof: Option<Box<dyn Write>>,
+    // the trail length right after the initial unit propagation in `parse_dimacs`; assumptions
+    // and the decisions/implications they cause never unwind past this point
+    root_level: usize,
+    // conflicts seen since the last restart
+    conflicts_since_restart: usize,
+    // the next index to draw from the Luby sequence when deciding whether to restart
+    restart_idx: usize,
+    // activity score per clause, parallel to `clauses`; only meaningful for learned clauses
+    clause_activity: Vec<f64>,
+    // amount `clause_activity` is bumped by; grows over time so recently-used clauses weigh more
+    clause_activity_inc: f64,
+    // number of learned clauses not yet deleted by `reduce_learned_clauses`
+    num_learned: usize,
+    // once `num_learned` exceeds this, the learned-clause database is reduced; grows after every
+    // reduction so later reductions don't thrash on clauses the solver still needs
+    learned_budget: usize,
 }
 
 impl Formula {
-    /// Assign a literal, performing unit propagation
+    /// Assign a decision literal, performing unit propagation
     fn assign(&mut self, lit: Literal) {
-        self.next_literal_id = lit.id + 1;
+        self.assign_with_reason(lit, None);
+    }
+
+    /// Assign a literal, performing unit propagation. `reason` is the clause that forced the
+    /// assignment, or `None` if `lit` is a decision literal.
+    fn assign_with_reason(&mut self, lit: Literal, reason: Option<usize>) {
         self.assign_history.push(vec![]);
 
-        fn inner(formula: &mut Formula, lit: Literal) {
+        fn inner(formula: &mut Formula, lit: Literal, reason: Option<usize>) {
+            let level = formula.assign_history.len() - 1;
             formula.assignment.assign(lit);
+            formula.var_level[lit.id] = level;
+            formula.var_reason[lit.id] = reason;
+            formula.phase[lit.id] = lit.negated;
+            formula.var_heap.remove(lit.id, &formula.activity);
             formula.assign_history.last_mut().unwrap().push(lit);
-            for i in 0..formula.clause_indices[lit.id].len() {
-                let (clause_idx, negated) = formula.clause_indices[lit.id][i];
-                if lit.negated != negated {
-                    let clause = &formula.clauses[clause_idx].0;
-                    let num_literals = clause.num_literals(formula);
-                    if num_literals == 0 {
+            formula.num_assigned += 1;
+
+            // `lit` just became true, so clauses watching `!lit` (now false) may need a new watch
+            let watched = lit_index(!lit);
+            let mut i = 0;
+            while i < formula.watches[watched].len() {
+                let clause_idx = formula.watches[watched][i];
+                match formula.update_watch(clause_idx, !lit) {
+                    WatchResult::Moved => {
+                        formula.watches[watched].swap_remove(i);
+                    }
+                    WatchResult::Satisfied => i += 1,
+                    WatchResult::Unit(unit_lit) => {
+                        i += 1;
+                        inner(formula, unit_lit, Some(clause_idx));
+                        if formula.unsolvable { return; }
+                    }
+                    WatchResult::Conflict => {
                         formula.unsolvable = true;
+                        formula.conflict_clause = Some(clause_idx);
                         return;
-                    } else if num_literals == 1 {
-                        // TODO: Optimise?
-                        let unit_lit = clause.iter(formula).next().unwrap();
-                        inner(formula, unit_lit);
                     }
-                } else if !formula.clauses[clause_idx].1 {
-                    formula.clauses[clause_idx].1 = true;
-                    formula.remaining_clauses -= 1;
                 }
             }
         }
 
-        inner(self, lit);
+        inner(self, lit, reason);
     }
 
-    /// Un-assign a literal, undoing unit propagation
-    fn un_assign(&mut self, lit: Literal) {
-        self.next_literal_id = lit.id;
+    /// Try to find a replacement for the watched literal `false_lit` (known to have just been
+    /// falsified) in `clauses[clause_idx]`, which keeps its two watched literals at positions 0
+    /// and 1.
+    fn update_watch(&mut self, clause_idx: usize, false_lit: Literal) -> WatchResult {
+        if self.clauses[clause_idx].literals[0] == false_lit {
+            self.clauses[clause_idx].literals.swap(0, 1);
+        }
+        let other = self.clauses[clause_idx].literals[0];
+        if self.assignment.assigned(other) {
+            return WatchResult::Satisfied;
+        }
+        let len = self.clauses[clause_idx].literals.len();
+        for i in 2..len {
+            let candidate = self.clauses[clause_idx].literals[i];
+            if !self.assignment.assigned(!candidate) {
+                self.clauses[clause_idx].literals.swap(1, i);
+                let watched = lit_index(candidate);
+                self.watches[watched].push(clause_idx);
+                return WatchResult::Moved;
+            }
+        }
+        if self.assignment.assigned(!other) {
+            WatchResult::Conflict
+        } else {
+            WatchResult::Unit(other)
+        }
+    }
+
+    /// Un-assign the most recently assigned decision (and everything it implied). Watches need
+    /// no maintenance on backtrack: they only ever point at a clause's two watched literals,
+    /// which stay valid regardless of what's currently assigned.
+    fn un_assign(&mut self) {
         self.unsolvable = false;
-        for lit in self.assign_history.pop().unwrap() {
+        self.conflict_clause = None;
+        let frame = self.assign_history.pop().unwrap();
+        self.num_assigned -= frame.len();
+        for lit in frame {
             self.assignment.un_assign(lit);
-            for &(clause_idx, negated) in &self.clause_indices[lit.id] {
-                if lit.negated == negated {
-                    let (clause, removed) = &self.clauses[clause_idx];
-                    if *removed && !clause.solved(self) {
-                        self.clauses[clause_idx].1 = false;
-                        self.remaining_clauses += 1;
-                    }
-                }
+            self.var_heap.push(lit.id, &self.activity);
+        }
+    }
+
+    /// Write a DRAT line for `literals` to the proof, if one is being recorded. `deletion`
+    /// prefixes the line with `d`, per the DRAT text format.
+    fn write_proof_line(&mut self, literals: &[Literal], deletion: bool) {
+        if let Some(proof) = &mut self.proof {
+            if deletion {
+                write!(proof, "d ").ok();
             }
+            for lit in literals {
+                write!(proof, "{} ", lit.to_dimacs()).ok();
+            }
+            writeln!(proof, "0").ok();
         }
     }
 }
 
 impl<'a> IntoIterator for &'a Formula {
     type Item = &'a Clause;
-    type IntoIter = FormulaIter<'a>;
+    type IntoIter = std::slice::Iter<'a, Clause>;
 
     fn into_iter(self) -> Self::IntoIter {
-        FormulaIter {
-            iter: self.clauses.iter(),
-        }
+        self.clauses.iter()
     }
 }
 
-/// A disjunction of some literals
-pub struct Clause(Vec<Literal>);
+/// An indexed max-heap of variable ids ordered by activity, supporting efficient removal and
+/// re-insertion as variables get assigned and un-assigned
+struct VarHeap {
+    heap: Vec<usize>,
+    // position[var] is var's index in `heap`, or `usize::MAX` while it's assigned (not in the heap)
+    position: Vec<usize>,
+}
 
-impl Clause {
-    fn new() -> Self {
-        Clause(vec![])
+impl VarHeap {
+    fn new(num_vars: usize) -> Self {
+        VarHeap {
+            heap: (0..num_vars).collect(),
+            position: (0..num_vars).collect(),
+        }
     }
 
-    fn add(&mut self, lit: Literal) {
-        self.0.push(lit);
+    /// Return, without removing, the unassigned variable with the highest activity, if any remain
+    fn peek(&self) -> Option<usize> {
+        self.heap.first().copied()
     }
 
-    fn solved(&self, formula: &Formula) -> bool {
-        self.0.iter().any(|&l| formula.assignment.assigned(l))
+    /// Add a newly-unassigned variable back into the heap
+    fn push(&mut self, var: usize, activity: &[f64]) {
+        let pos = self.heap.len();
+        self.heap.push(var);
+        self.position[var] = pos;
+        self.sift_up(pos, activity);
     }
 
-    fn num_literals(&self, formula: &Formula) -> usize {
-        self.iter(formula).count()
+    /// Remove a specific, just-assigned variable from the heap
+    fn remove(&mut self, var: usize, activity: &[f64]) {
+        let pos = self.position[var];
+        let last_pos = self.heap.len() - 1;
+        self.heap.swap(pos, last_pos);
+        self.heap.pop();
+        self.position[var] = usize::MAX;
+        if pos < self.heap.len() {
+            self.position[self.heap[pos]] = pos;
+            self.sift_up(pos, activity);
+            self.sift_down(pos, activity);
+        }
     }
 
-    fn iter<'a>(&'a self, formula: &'a Formula) -> ClauseIter {
-        ClauseIter {
-            iter: self.0.iter(),
-            assignment: &formula.assignment,
+    /// `var`'s activity just increased; restore the heap property
+    fn bump(&mut self, var: usize, activity: &[f64]) {
+        let pos = self.position[var];
+        if pos != usize::MAX {
+            self.sift_up(pos, activity);
         }
     }
 
+    fn sift_up(&mut self, mut pos: usize, activity: &[f64]) {
+        while pos > 0 {
+            let parent = (pos - 1) / 2;
+            if activity[self.heap[parent]] >= activity[self.heap[pos]] {
+                break;
+            }
+            self.heap.swap(parent, pos);
+            self.position[self.heap[parent]] = parent;
+            self.position[self.heap[pos]] = pos;
+            pos = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut pos: usize, activity: &[f64]) {
+        loop {
+            let (left, right) = (2 * pos + 1, 2 * pos + 2);
+            let mut largest = pos;
+            if left < self.heap.len() && activity[self.heap[left]] > activity[self.heap[largest]] {
+                largest = left;
+            }
+            if right < self.heap.len() && activity[self.heap[right]] > activity[self.heap[largest]] {
+                largest = right;
+            }
+            if largest == pos {
+                break;
+            }
+            self.heap.swap(pos, largest);
+            self.position[self.heap[pos]] = pos;
+            self.position[self.heap[largest]] = largest;
+            pos = largest;
+        }
+    }
+}
+
+/// A disjunction of some literals. The first two literals are the clause's watched literals.
+pub struct Clause {
+    literals: Vec<Literal>,
+    // this clause's Literal Block Distance at the time it was learned, or `None` for an original
+    // problem clause, which database reduction never considers for deletion
+    lbd: Option<usize>,
+    // set once `reduce_learned_clauses` drops this clause; its slot is kept (and its literals
+    // cleared) rather than shifting every later clause's index
+    deleted: bool,
+}
+
+impl Clause {
+    fn new() -> Self {
+        Clause { literals: vec![], lbd: None, deleted: false }
+    }
+
+    /// Build a freshly-learned clause, recording the LBD it was learned with
+    fn learned(literals: Vec<Literal>, lbd: usize) -> Self {
+        Clause { literals, lbd: Some(lbd), deleted: false }
+    }
+
+    fn add(&mut self, lit: Literal) {
+        self.literals.push(lit);
+    }
+
+    /// All literals in the clause, regardless of their current assignment
+    fn literals(&self) -> &[Literal] {
+        &self.literals
+    }
+
     /// If the clauses contains one literal, return it, None otherwise
     fn get_unit_literal(&self) -> Option<Literal> {
-        if self.0.len() == 1 {
-            Some(unsafe { *self.0.get_unchecked(0) })
+        if self.literals.len() == 1 {
+            Some(unsafe { *self.literals.get_unchecked(0) })
         } else {
             None
         }
@@ -124,12 +314,19 @@ pub struct Literal {
 }
 
 impl Literal {
-    fn from_var(var: isize) -> Self {
+    /// Build the literal for a signed DIMACS variable, e.g. `3` or `-42`
+    pub fn from_var(var: isize) -> Self {
         Literal {
             id: var.abs() as usize - 1,
             negated: var < 0,
         }
     }
+
+    /// The signed DIMACS variable this literal corresponds to, e.g. `3` or `-42`
+    fn to_dimacs(self) -> isize {
+        let var = self.id as isize + 1;
+        if self.negated { -var } else { var }
+    }
 }
 
 impl Not for Literal {
@@ -143,42 +340,20 @@ impl Not for Literal {
     }
 }
 
-pub struct FormulaIter<'a> {
-    iter: std::slice::Iter<'a, (Clause, bool)>,
-}
-
-impl<'a> Iterator for FormulaIter<'a> {
-    type Item = &'a Clause;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.iter.next() {
-            Some((c, d)) =>
-                if *d { self.next() } else { Some(c) },
-            None => None
-        }
-    }
-}
-
-pub struct ClauseIter<'a> {
-    iter: std::slice::Iter<'a, Literal>,
-    assignment: &'a Assignment,
-}
-
-impl<'a> Iterator for ClauseIter<'a> {
-    type Item = Literal;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.iter.next() {
-            Some(&l) =>
-                if self.assignment.assigned(!l) { self.next() } else { Some(l) },
-            None => None
-        }
-    }
+/// The outcome of [`Formula::solve_under`]
+pub enum SolveResult {
+    /// A satisfying assignment
+    Sat(Assignment),
+    /// The assumptions are contradictory; a subset of them that is unsatisfiable together with
+    /// the formula. It holds every assumption the conflict traces back to and is not necessarily
+    /// minimal.
+    Unsat(Vec<Literal>),
 }
 
 /// The assigned literals
 /// Each spot in the Vec is either a bool determining whether the assigned literal is negated
 /// or None, if neither literal with that id is assigned
+#[derive(Clone)]
 pub struct Assignment(Vec<Option<bool>>);
 
 impl Assignment {
@@ -206,4 +381,4 @@ impl fmt::Display for Assignment {
         }
         write!(f, "0")
     }
-}
\ No newline at end of file
+}
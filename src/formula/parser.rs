@@ -34,35 +34,60 @@ impl Formula {
         let mut formula = Formula {
             clauses: Vec::with_capacity(num_clauses),
             assignment: Assignment::new(num_vars),
-            clause_indices: vec![vec![]; num_vars],
+            watches: vec![vec![]; num_vars * 2],
             assign_history: vec![],
-            remaining_clauses: num_clauses,
             unsolvable: false,
-            next_literal_id: 0
+            num_assigned: 0,
+            var_level: vec![0; num_vars],
+            var_reason: vec![None; num_vars],
+            conflict_clause: None,
+            activity: vec![0.0; num_vars],
+            activity_inc: 1.0,
+            var_heap: VarHeap::new(num_vars),
+            phase: vec![false; num_vars],
+            proof: None,
+            root_level: 0,
+            conflicts_since_restart: 0,
+            restart_idx: 1,
+            clause_activity: Vec::with_capacity(num_clauses),
+            clause_activity_inc: 1.0,
+            num_learned: 0,
+            learned_budget: (num_clauses / 3).max(100),
         };
 
         let pos = buf.position() as usize;
         let buf = &buf.into_inner()[pos..];
         let mut clause_iter = buf.trim_end().split(" 0");
 
-        'outer: for (clause_idx, clause_str) in (&mut clause_iter).take(num_clauses).enumerate() {
+        'outer: for clause_str in (&mut clause_iter).take(num_clauses) {
             let mut clause = Clause::new();
 
             for v in clause_str.split_whitespace() {
                 let v: isize = v.parse().map_err(|_| format!("Illegal variable '{}'", v))?;
                 let lit = Literal::from_var(v);
                 // Check if we have a | !a, which we rely upon not existing in the solver
-                if clause.0.contains(&!lit) {
-                    formula.remaining_clauses -= 1;
+                if clause.literals().contains(&!lit) {
                     continue 'outer;
                 }
                 clause.add(lit);
             }
 
-            for lit in &clause.0 {
-                formula.clause_indices[lit.id].push((clause_idx, lit.negated));
+            // an empty clause can never be satisfied, regardless of anything else in the formula
+            if clause.literals().is_empty() {
+                formula.unsolvable = true;
             }
-            formula.clauses.push((clause, false));
+
+            // index the clause will actually occupy in `formula.clauses`, which may be behind the
+            // raw clause count once tautological clauses above have been skipped
+            let clause_idx = formula.clauses.len();
+
+            // clauses shorter than two literals are handled by the initial unit propagation below
+            if clause.literals().len() >= 2 {
+                formula.watches[lit_index(clause.literals()[0])].push(clause_idx);
+                formula.watches[lit_index(clause.literals()[1])].push(clause_idx);
+            }
+            formula.clauses.push(clause);
+            formula.clause_activity.push(0.0);
         }
 
         match clause_iter.next() {
@@ -75,11 +100,21 @@ impl Formula {
     /// Unit propagation
     fn simplify(mut self) -> Self {
         for i in 0..self.clauses.len() {
-            if let Some(l) = self.clauses[i].0.get_unit_literal() {
-                self.assign(l);
+            if self.unsolvable {
+                break;
+            }
+            if let Some(l) = self.clauses[i].get_unit_literal() {
+                if self.assignment.assigned(!l) {
+                    // the opposite value is already assigned, by an earlier unit clause or its
+                    // propagation
+                    self.unsolvable = true;
+                } else if !self.assignment.assigned(l) {
+                    self.assign(l);
+                }
             }
         }
 
+        self.root_level = self.assign_history.len();
         self
     }
 }
\ No newline at end of file
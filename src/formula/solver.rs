@@ -1,9 +1,24 @@
 use super::*;
+use std::io::Write;
+
+// the conflict-count unit the Luby sequence is scaled by to get a restart threshold
+const RESTART_UNIT: usize = 100;
+
+/// The `u`-th term (1-indexed) of the Luby sequence 1, 1, 2, 1, 1, 2, 4, …, via the standard
+/// reluctant-doubling recurrence.
+fn luby(u: usize) -> usize {
+    if (u + 1).is_power_of_two() {
+        (u + 1).div_ceil(2)
+    } else {
+        let k = usize::BITS - 1 - u.leading_zeros(); // floor(log2(u))
+        luby(u - (1 << k) + 1)
+    }
+}
 
 impl Formula {
-    pub fn solve(mut self) -> Option<Assignment> {
+    pub fn solve(&mut self) -> Option<Assignment> {
         if self.dpll() {
-            Some(self.assignment)
+            Some(self.assignment.clone())
         } else {
             None
         }
@@ -11,32 +26,489 @@ impl Formula {
 
     /// The DPLL algorithm. Simplification happens on assignment
     fn dpll(&mut self) -> bool {
-        if self.remaining_clauses == 0 {
-            true
-        } else if self.unsolvable {
+        if self.unsolvable {
             false
+        } else if self.num_assigned == self.var_level.len() {
+            true
         } else {
             let next = self.next_un_assigned();
             self.assign(next);
             self.dpll() || {
-                self.un_assign(next);
+                self.un_assign();
                 self.assign(!next);
                 let res = self.dpll();
-                if !res { self.un_assign(!next) }
+                if !res { self.un_assign() }
                 res
             }
         }
     }
 
-    fn next_un_assigned(&self) -> Literal {
-        for id in self.next_literal_id.. {
-            if self.assignment.0[id].is_none() {
-                return Literal {
-                    id,
-                    negated: false,
+    /// Solve using conflict-driven clause learning instead of chronological backtracking.
+    /// An alternative to [`Formula::solve`] for instances where non-chronological backjumping
+    /// pays for the bookkeeping it costs.
+    pub fn solve_cdcl(&mut self) -> Option<Assignment> {
+        if self.cdcl() {
+            Some(self.assignment.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Like [`Formula::solve_cdcl`], but on UNSAT writes a DRAT proof to `out`: every learned
+    /// clause as an addition line, and the final empty clause once the top-level conflict is
+    /// reached. The proof can be checked by an external DRAT checker.
+    pub fn solve_with_proof(&mut self, out: impl Write + 'static) -> Option<Assignment> {
+        self.proof = Some(Box::new(out));
+        self.solve_cdcl()
+    }
+
+    /// Solve under a set of assumptions without discarding the clause database: learned clauses
+    /// of two or more literals (and the VSIDS activities built up learning them) are kept for the
+    /// next call. A learned unit clause is assigned rather than stored, so the next call's reset
+    /// to the root level undoes it. Each
+    /// assumption is assigned as if it were a decision, in order; if propagating one of them
+    /// conflicts, the conflict is traced back to the assumptions it depends on instead of
+    /// learning a new clause, and that subset is returned as the final conflict.
+    pub fn solve_under(&mut self, assumptions: &[Literal]) -> SolveResult {
+        self.reset_to_root();
+
+        for &lit in assumptions {
+            if self.assignment.assigned(!lit) {
+                // already forced to the opposite value before this assumption was even made: the
+                // core is this assumption plus whatever earlier assumptions forced `!lit`
+                let mut core = match self.var_reason[lit.id] {
+                    Some(reason) => self.analyze_final(reason),
+                    None if self.var_level[lit.id] >= self.root_level => vec![!lit],
+                    None => vec![],
                 };
+                core.push(lit);
+                return SolveResult::Unsat(core);
+            }
+            if !self.assignment.assigned(lit) {
+                self.assign_with_reason(lit, None);
+            }
+            if self.unsolvable {
+                let conflict = self.conflict_clause.unwrap();
+                return SolveResult::Unsat(self.analyze_final(conflict));
+            }
+        }
+
+        // taken after the assumptions so backjumps and restarts never undo them
+        let base_level = self.assign_history.len();
+        if self.cdcl_from(base_level) {
+            SolveResult::Sat(self.assignment.clone())
+        } else {
+            SolveResult::Unsat(vec![])
+        }
+    }
+
+    /// Unwind the trail back to [`Formula::root_level`], discarding every assumption from a
+    /// previous [`Formula::solve_under`] call (and everything it implied) while keeping the
+    /// clause database and VSIDS state intact.
+    fn reset_to_root(&mut self) {
+        while self.assign_history.len() > self.root_level {
+            self.un_assign();
+        }
+    }
+
+    /// Trace a conflict that arose while propagating assumptions back to the assumption literals
+    /// it depends on, by walking the reason graph from `conflict_idx` down to the literals with
+    /// no reason (the assumptions themselves, assigned as decisions in [`Formula::solve_under`]).
+    /// The result is a subset of the assumptions whose conjunction is unsatisfiable.
+    fn analyze_final(&self, conflict_idx: usize) -> Vec<Literal> {
+        let mut seen = vec![false; self.var_level.len()];
+        let mut stack = vec![conflict_idx];
+        let mut core = vec![];
+
+        while let Some(clause_idx) = stack.pop() {
+            for &lit in self.clauses[clause_idx].literals() {
+                // literals assigned below `root_level` are implied by the formula alone and
+                // depend on no assumption
+                if seen[lit.id] || self.var_level[lit.id] < self.root_level {
+                    continue;
+                }
+                seen[lit.id] = true;
+                match self.var_reason[lit.id] {
+                    // `lit` is false under the current assignment, so the assumption that forced
+                    // it is its negation
+                    None => core.push(!lit),
+                    Some(reason) => stack.push(reason),
+                }
+            }
+        }
+
+        core
+    }
+
+    fn cdcl(&mut self) -> bool {
+        if self.unsolvable {
+            self.write_proof_line(&[], false);
+            return false;
+        }
+        // decisions never unwind past the assignments already implied before any were made
+        let base_level = self.assign_history.len();
+        self.cdcl_from(base_level)
+    }
+
+    fn cdcl_from(&mut self, mut base_level: usize) -> bool {
+        loop {
+            if self.unsolvable {
+                if self.assign_history.len() == base_level {
+                    self.write_proof_line(&[], false);
+                    return false;
+                }
+                let conflict = self.conflict_clause.unwrap();
+                let (learned, target_len) = self.analyze_conflict(conflict);
+                let asserting = learned[0];
+
+                let target = target_len.max(base_level);
+                while self.assign_history.len() > target {
+                    self.un_assign();
+                }
+
+                if learned.len() == 1 {
+                    // a single-literal learned clause is a fact, not a clause worth keeping: pushed
+                    // into the database unwatched, it would never propagate again once something
+                    // un-assigns it, and the same unit would be re-derived forever. Assign it
+                    // directly instead, and raise the floor restarts/backjumps clamp to so it can
+                    // never be un-assigned for the rest of this call.
+                    self.write_proof_line(&learned, false);
+                    self.assign_with_reason(asserting, None);
+                    base_level = self.assign_history.len();
+                } else {
+                    let learned_idx = self.learn_clause(learned);
+                    self.assign_with_reason(asserting, Some(learned_idx));
+                }
+
+                // restart periodically, per the Luby sequence, to escape unproductive regions of
+                // the search tree; learned clauses and VSIDS activities survive the backtrack
+                self.conflicts_since_restart += 1;
+                if self.conflicts_since_restart > luby(self.restart_idx) * RESTART_UNIT {
+                    while self.assign_history.len() > base_level {
+                        self.un_assign();
+                    }
+                    self.conflicts_since_restart = 0;
+                    self.restart_idx += 1;
+                }
+
+                if self.num_learned > self.learned_budget {
+                    self.reduce_learned_clauses();
+                }
+            } else if self.num_assigned == self.var_level.len() {
+                return true;
+            } else {
+                let next = self.next_un_assigned();
+                self.assign(next);
+            }
+        }
+    }
+
+    /// Resolve the conflicting clause against the reason clauses of literals assigned at the
+    /// current decision level until a single literal at that level remains (the first unique
+    /// implication point). Returns the learned clause, with the asserting literal first, and
+    /// the trail length to backjump to (one past the second-highest decision level among its
+    /// literals).
+    fn analyze_conflict(&mut self, conflict_idx: usize) -> (Vec<Literal>, usize) {
+        let current_level = self.assign_history.len() - 1;
+        let mut seen = vec![false; self.var_level.len()];
+        let mut learned = vec![];
+        let mut counter = 0;
+        let mut reason_idx = conflict_idx;
+        let mut skip = None;
+        let mut trail_pos = self.assign_history[current_level].len();
+
+        loop {
+            self.bump_clause_activity(reason_idx);
+            let resolvent: Vec<Literal> = self.clauses[reason_idx].literals().to_vec();
+            for lit in resolvent {
+                if Some(lit) == skip || seen[lit.id] {
+                    continue;
+                }
+                seen[lit.id] = true;
+                self.bump_activity(lit.id);
+                if self.var_level[lit.id] == current_level {
+                    counter += 1;
+                } else {
+                    learned.push(lit);
+                }
+            }
+
+            // walk the current level's assignments backwards for the next literal under dispute
+            loop {
+                trail_pos -= 1;
+                let lit = self.assign_history[current_level][trail_pos];
+                if seen[lit.id] {
+                    seen[lit.id] = false;
+                    skip = Some(lit);
+                    counter -= 1;
+                    if counter == 0 {
+                        let mut clause = vec![!lit];
+                        clause.extend(learned);
+                        let clause = self.minimize_clause(clause, &mut seen);
+                        // `+ 1`: this is a trail length to keep, not a level index. Without it,
+                        // the backjump below un-assigns the very frame the learned clause's
+                        // second-highest-level literal still depends on.
+                        let target_len = clause[1..].iter()
+                            .map(|l| self.var_level[l.id] + 1)
+                            .max()
+                            .unwrap_or(0);
+                        // decay activities so more recent conflicts weigh more heavily
+                        self.activity_inc /= 0.95;
+                        self.clause_activity_inc /= 0.999;
+                        return (clause, target_len);
+                    }
+                    reason_idx = self.var_reason[lit.id].unwrap();
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Drop literals from a freshly-derived learned clause whose absence wouldn't change what the
+    /// clause implies. `seen` must mark exactly the variables of `clause[1..]` (as it does right
+    /// after [`Formula::analyze_conflict`] derives the clause); on return it also marks the
+    /// variables visited by probes that proved a literal redundant. The asserting literal,
+    /// `clause[0]`, is never removed.
+    fn minimize_clause(&self, clause: Vec<Literal>, seen: &mut [bool]) -> Vec<Literal> {
+        let mut minimized = vec![clause[0]];
+        for &lit in &clause[1..] {
+            if !self.literal_redundant(lit, seen) {
+                minimized.push(lit);
+            }
+        }
+        minimized
+    }
+
+    /// `lit` is redundant in the learned clause if every literal in the reason clause of the
+    /// variable it resolved away is itself already in the clause (i.e. `seen`) or recursively
+    /// redundant. Probes the reason graph depth-first, marking newly-visited variables in `seen`
+    /// as it goes; if it bottoms out at a decision variable (no reason) that isn't already in the
+    /// clause, `lit` isn't redundant after all, and every mark made during this probe is rolled
+    /// back via `clear_list` so it doesn't taint later probes.
+    fn literal_redundant(&self, lit: Literal, seen: &mut [bool]) -> bool {
+        let mut stack = vec![lit];
+        let mut clear_list = vec![];
+
+        while let Some(l) = stack.pop() {
+            let reason = match self.var_reason[l.id] {
+                Some(reason) => reason,
+                None => {
+                    for id in clear_list {
+                        seen[id] = false;
+                    }
+                    return false;
+                }
+            };
+            for &rl in self.clauses[reason].literals() {
+                if rl.id == l.id || seen[rl.id] {
+                    continue;
+                }
+                if self.var_reason[rl.id].is_none() {
+                    for id in clear_list {
+                        seen[id] = false;
+                    }
+                    return false;
+                }
+                seen[rl.id] = true;
+                clear_list.push(rl.id);
+                stack.push(rl);
+            }
+        }
+
+        true
+    }
+
+    /// Add a learned clause to the database, registering its watches and recording the LBD
+    /// (number of distinct decision levels among its literals) it was learned with
+    fn learn_clause(&mut self, literals: Vec<Literal>) -> usize {
+        self.write_proof_line(&literals, false);
+        let mut levels: Vec<usize> = literals.iter().map(|l| self.var_level[l.id]).collect();
+        levels.sort_unstable();
+        levels.dedup();
+        let lbd = levels.len();
+
+        let idx = self.clauses.len();
+        if literals.len() >= 2 {
+            self.watches[lit_index(literals[0])].push(idx);
+            self.watches[lit_index(literals[1])].push(idx);
+        }
+        self.clauses.push(Clause::learned(literals, lbd));
+        self.clause_activity.push(0.0);
+        self.num_learned += 1;
+        idx
+    }
+
+    /// Bump a learned clause's activity, rescaling everything down if it would overflow. A no-op
+    /// for original problem clauses, which carry no activity score.
+    fn bump_clause_activity(&mut self, idx: usize) {
+        if self.clauses[idx].lbd.is_none() {
+            return;
+        }
+        self.clause_activity[idx] += self.clause_activity_inc;
+        if self.clause_activity[idx] > 1e100 {
+            for a in &mut self.clause_activity {
+                *a *= 1e-100;
+            }
+            self.clause_activity_inc *= 1e-100;
+        }
+    }
+
+    /// Once the learned-clause count exceeds its budget, discard the worst half of them (by LBD,
+    /// breaking ties with clause activity) to keep propagation fast and memory bounded. Clauses
+    /// with LBD <= 2 or that are currently the reason for an assigned literal are never removed.
+    /// The budget grows afterward so reduction doesn't thrash on clauses the solver still needs.
+    fn reduce_learned_clauses(&mut self) {
+        let active_reasons: std::collections::HashSet<usize> = self.assign_history.iter()
+            .flatten()
+            .filter_map(|lit| self.var_reason[lit.id])
+            .collect();
+
+        let mut candidates: Vec<usize> = (0..self.clauses.len())
+            .filter(|&idx| {
+                let clause = &self.clauses[idx];
+                !clause.deleted
+                    && clause.lbd.is_some_and(|lbd| lbd > 2)
+                    && !active_reasons.contains(&idx)
+            })
+            .collect();
+
+        // worst (highest LBD, then lowest activity) first
+        candidates.sort_by(|&a, &b| {
+            self.clauses[b].lbd.cmp(&self.clauses[a].lbd)
+                .then(self.clause_activity[a].partial_cmp(&self.clause_activity[b]).unwrap())
+        });
+
+        let to_remove = candidates.len() / 2;
+        for &idx in &candidates[..to_remove] {
+            self.delete_clause(idx);
+        }
+
+        self.learned_budget += self.learned_budget / 5;
+    }
+
+    /// Remove a learned clause from the watch lists and mark its slot deleted, keeping every
+    /// other clause's index stable. Emits a DRAT deletion line if a proof is being recorded.
+    fn delete_clause(&mut self, idx: usize) {
+        let literals = self.clauses[idx].literals().to_vec();
+        self.write_proof_line(&literals, true);
+        if literals.len() >= 2 {
+            for &lit in &literals[..2] {
+                self.watches[lit_index(lit)].retain(|&c| c != idx);
             }
         }
-        unreachable!()
+        self.clauses[idx].literals.clear();
+        self.clauses[idx].deleted = true;
+        self.num_learned -= 1;
+    }
+
+    /// Peek the highest-VSIDS-activity unassigned variable, branching on its saved phase. Left in
+    /// the heap: `assign`/`assign_with_reason` is the sole remover, so it stays in sync with the
+    /// single `var_heap.push` done on un-assignment.
+    fn next_un_assigned(&mut self) -> Literal {
+        let id = self.var_heap.peek().unwrap();
+        Literal {
+            id,
+            negated: self.phase[id],
+        }
+    }
+
+    /// Bump a variable's VSIDS activity, rescaling everything down if it would overflow
+    fn bump_activity(&mut self, var: usize) {
+        self.activity[var] += self.activity_inc;
+        if self.activity[var] > 1e100 {
+            for a in &mut self.activity {
+                *a *= 1e-100;
+            }
+            self.activity_inc *= 1e-100;
+        }
+        self.var_heap.bump(var, &self.activity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static TEST_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    /// parse_dimacs only accepts a `File`, so tests round-trip their input through a scratch file
+    fn parse(dimacs: &str) -> Formula {
+        let n = TEST_FILE_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("spicy_sat_solver_test_{}_{n}.cnf", std::process::id()));
+        std::fs::write(&path, dimacs).unwrap();
+        let formula = Formula::parse_dimacs(File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).ok();
+        formula
+    }
+
+    #[test]
+    fn unit_learned_clauses_do_not_loop_forever() {
+        // regression case for a learned unit clause getting pushed unwatched, then silently lost
+        // to a later backjump and re-derived forever
+        let mut formula = parse("p cnf 2 6\n1 -2 0 -2 -1 0 -2 -1 0 1 2 0 2 -1 0 1 -2 0");
+        assert!(formula.solve_cdcl().is_none());
+    }
+
+    #[test]
+    fn dpll_reports_unsat_on_a_conflict_at_the_last_variable() {
+        // same formula as above, exercised through the chronological-backtracking solver: it used
+        // to report SAT with a clause-violating assignment because `dpll` checked "all assigned"
+        // before `unsolvable`
+        let mut formula = parse("p cnf 2 6\n1 -2 0 -2 -1 0 -2 -1 0 1 2 0 2 -1 0 1 -2 0");
+        assert!(formula.solve().is_none());
+    }
+
+    #[test]
+    fn simplify_does_not_panic_on_a_repeated_unit_literal() {
+        // "1" and "2" are both unit clauses, and "-1 2" propagates var 2 again before the parser
+        // would otherwise re-process its own unit clause for it
+        let mut formula = parse("p cnf 2 3\n1 0 -1 2 0 2 0");
+        let assignment = formula.solve_cdcl().unwrap();
+        assert!(assignment.assigned(Literal::from_var(1)));
+        assert!(assignment.assigned(Literal::from_var(2)));
+    }
+
+    #[test]
+    fn empty_clause_is_unsatisfiable() {
+        let mut formula = parse("p cnf 1 2\n1 0 0");
+        assert!(formula.solve_cdcl().is_none());
+    }
+
+    /// Evaluate `clauses` (each literal a signed DIMACS variable, 1-indexed) against every
+    /// assignment of `num_vars` variables
+    fn brute_force_sat(num_vars: usize, clauses: &[&[isize]]) -> bool {
+        (0..1u32 << num_vars).any(|assignment| {
+            clauses.iter().all(|clause| {
+                clause.iter().any(|&lit| {
+                    let value = (assignment >> (lit.unsigned_abs() as usize - 1)) & 1 == 1;
+                    (lit > 0) == value
+                })
+            })
+        })
+    }
+
+    #[test]
+    fn matches_brute_force_on_small_formulas() {
+        let cases: &[(&str, usize, &[&[isize]])] = &[
+            ("p cnf 2 6\n1 -2 0 -2 -1 0 -2 -1 0 1 2 0 2 -1 0 1 -2 0", 2,
+                &[&[1, -2][..], &[-2, -1], &[-2, -1], &[1, 2], &[2, -1], &[1, -2]]),
+            ("p cnf 2 3\n1 0 -1 2 0 2 0", 2,
+                &[&[1][..], &[-1, 2], &[2]]),
+            // at most one of the three variables may be true and at least one must be: the first
+            // decision (x1 true) propagates x2 and x3 to false, so this is solved without a conflict
+            ("p cnf 3 4\n1 2 3 0 -1 -2 0 -2 -3 0 -1 -3 0", 3,
+                &[&[1, 2, 3][..], &[-1, -2], &[-2, -3], &[-1, -3]]),
+        ];
+
+        for (dimacs, num_vars, clauses) in cases {
+            let mut formula = parse(dimacs);
+            assert_eq!(
+                formula.solve_cdcl().is_some(),
+                brute_force_sat(*num_vars, clauses),
+                "mismatch on {dimacs:?}",
+            );
+        }
     }
 }
\ No newline at end of file